@@ -0,0 +1,184 @@
+//! Over-the-air firmware update (DFU) subsystem.
+//!
+//! New firmware is streamed in length-prefixed `offset, bytes` chunks --
+//! the same framing the RPC layer already uses for every other call --
+//! into a flash partition reserved for staging (see [`layout`]).
+//! `dfu_commit` then writes a small persistent marker recording that a
+//! swap is pending and resets the board. [`on_boot`] reconciles that
+//! marker at startup into a [`BootState`] the host can read back with
+//! `dfu_state`: `Swapped` is reported on the first boot after `dfu_commit`,
+//! before the image has confirmed itself, and `DfuDetached` on any later
+//! boot where that marker is still unconfirmed (no rollback is performed;
+//! the state is only reported). The host is expected to
+//! self-test a `Swapped` image and call `mark_booted`; if it never does,
+//! the marker is left unconfirmed and the next boot reports
+//! `DfuDetached` instead of `Boot`.
+//!
+//! What's implemented here is the marker bookkeeping and RPC plumbing
+//! only. The two pieces that make a swap actually happen are still
+//! missing: [`crate::hardware::Flash`] doesn't yet drive real erase/program
+//! (`dfu_write`/`dfu_commit` report failure rather than silently doing
+//! nothing), and there's no bootloader stage that copies the staged
+//! image over the running one on seeing a pending marker -- `on_boot`
+//! only reports what it finds, it doesn't perform a swap. Both are
+//! follow-up work.
+
+use crate::hardware::{Flash, FlashError};
+use core::cell::Cell;
+use critical_section::Mutex;
+use log::warn;
+
+/// Flash layout for the DFU subsystem. Offsets are flash-relative and
+/// chosen to fit within the Teensy 4.0's 2 MiB flash; they are not yet
+/// backed by a real partition carve-out in a linker script (no linker
+/// script ships in this tree), so treat them as provisional until that
+/// lands.
+pub mod layout {
+    /// Partition new firmware images are staged into before a swap.
+    pub const DFU_PARTITION_START: u32 = 0x0018_0000;
+    pub const DFU_PARTITION_LEN: u32 = 0x0007_F000;
+    /// Single flash sector holding the persistent swap marker.
+    pub const STATE_REGION_START: u32 = 0x001F_F000;
+    pub const STATE_REGION_LEN: u32 = 0x0000_1000;
+}
+
+const MARKER_MAGIC: u32 = 0xDF00_B007;
+const MARKER_LEN: usize = 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum BootState {
+    /// Running a confirmed image; no swap pending.
+    Boot = 0,
+    /// Running a freshly swapped image that hasn't confirmed itself yet.
+    Swapped = 1,
+    /// A pending swap was never confirmed. Only reported: no rollback to a
+    /// previous image is performed.
+    DfuDetached = 2,
+}
+
+struct Marker {
+    pending: bool,
+    confirmed: bool,
+    /// Set the first time [`on_boot`] reports `Swapped` for this marker, so
+    /// a *second* boot without an intervening `mark_booted` can tell "still
+    /// awaiting self-test" (never reported) apart from "self-test never
+    /// happened" (already reported once, still unconfirmed) and report
+    /// `DfuDetached` instead of `Swapped` forever.
+    reported: bool,
+}
+
+impl Marker {
+    fn read(flash: &Flash) -> Option<Self> {
+        let mut raw = [0u8; MARKER_LEN];
+        flash.read(layout::STATE_REGION_START, &mut raw).ok()?;
+        let magic = u32::from_le_bytes(raw[0..4].try_into().unwrap());
+        if magic != MARKER_MAGIC {
+            return None;
+        }
+        Some(Self {
+            pending: raw[4] != 0,
+            confirmed: raw[5] != 0,
+            reported: raw[6] != 0,
+        })
+    }
+
+    fn write(&self, flash: &mut Flash) -> Result<(), FlashError> {
+        let mut raw = [0u8; MARKER_LEN];
+        raw[0..4].copy_from_slice(&MARKER_MAGIC.to_le_bytes());
+        raw[4] = self.pending as u8;
+        raw[5] = self.confirmed as u8;
+        raw[6] = self.reported as u8;
+        // Safety: the state region is carved out exclusively for this
+        // marker and never overlaps the running application image.
+        unsafe {
+            flash.erase(layout::STATE_REGION_START, layout::STATE_REGION_LEN)?;
+            flash.write(layout::STATE_REGION_START, &raw)?;
+        }
+        Ok(())
+    }
+}
+
+static BOOT_STATE: Mutex<Cell<BootState>> = Mutex::new(Cell::new(BootState::Boot));
+/// Length of the image currently being staged, set by `dfu_begin` and
+/// consulted by `dfu_write` to bounds-check incoming chunks.
+static STAGED_LEN: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Call once at startup, before `dfu_state` can be queried. Reconciles the
+/// persistent marker left by the previous boot into the in-memory
+/// [`BootState`].
+pub fn on_boot(flash: &mut Flash) {
+    let state = match Marker::read(flash) {
+        Some(marker) if marker.pending && !marker.confirmed && !marker.reported => {
+            // First boot since `dfu_commit`: this is the host's chance to
+            // self-test and call `mark_booted`. Record that we've reported
+            // it, so a later boot on the same unconfirmed marker knows the
+            // self-test window already passed.
+            let reported = Marker {
+                reported: true,
+                ..marker
+            };
+            if let Err(error) = reported.write(flash) {
+                warn!("on_boot: failed to record swap as reported: {error:?}");
+            }
+            BootState::Swapped
+        }
+        Some(marker) if marker.pending && !marker.confirmed => {
+            warn!("previous DFU swap was never confirmed, reporting rollback");
+            BootState::DfuDetached
+        }
+        _ => BootState::Boot,
+    };
+    critical_section::with(|cs| BOOT_STATE.borrow(cs).set(state));
+}
+
+pub fn state() -> BootState {
+    critical_section::with(|cs| BOOT_STATE.borrow(cs).get())
+}
+
+/// Erases the DFU partition and records the expected image length.
+pub fn begin(flash: &mut Flash, len: u32) -> Result<(), FlashError> {
+    // Safety: `DFU_PARTITION_START`/`LEN` never overlap the running image.
+    unsafe { flash.erase(layout::DFU_PARTITION_START, layout::DFU_PARTITION_LEN)? };
+    critical_section::with(|cs| STAGED_LEN.borrow(cs).set(len));
+    Ok(())
+}
+
+/// Writes one chunk of the staged image at `offset` bytes into the DFU
+/// partition.
+pub fn write_chunk(flash: &mut Flash, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+    let end = offset
+        .checked_add(data.len() as u32)
+        .ok_or(FlashError::OutOfRange)?;
+    let staged_len = critical_section::with(|cs| STAGED_LEN.borrow(cs).get());
+    if end > layout::DFU_PARTITION_LEN || end > staged_len {
+        return Err(FlashError::OutOfRange);
+    }
+    // Safety: bounds-checked against the DFU partition above.
+    unsafe { flash.write(layout::DFU_PARTITION_START + offset, data)? };
+    Ok(())
+}
+
+/// Records a pending-swap marker for the staged image. The caller is
+/// expected to reset the board immediately after this returns.
+pub fn commit(flash: &mut Flash) -> Result<(), FlashError> {
+    Marker {
+        pending: true,
+        confirmed: false,
+        reported: false,
+    }
+    .write(flash)
+}
+
+/// Confirms the currently-running (`Swapped`) image. Clears `pending` so
+/// later boots see a plain `Boot` instead of `DfuDetached`.
+pub fn mark_booted(flash: &mut Flash) -> Result<(), FlashError> {
+    Marker {
+        pending: false,
+        confirmed: true,
+        reported: true,
+    }
+    .write(flash)?;
+    critical_section::with(|cs| BOOT_STATE.borrow(cs).set(BootState::Boot));
+    Ok(())
+}
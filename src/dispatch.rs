@@ -0,0 +1,110 @@
+//! Extensible RPC dispatch table.
+//!
+//! `SerialComm::read` used to resolve an incoming `FUNCTION_HEADER` frame
+//! with a hardcoded `match function.as_str()`, so every new command meant
+//! editing the core serial loop. Handlers register themselves into this
+//! table instead (see `events::register_all`), decoupling the transport in
+//! `serial.rs` from the command set in `events.rs`. A built-in
+//! `list_functions` entry lets the host discover the device's registered
+//! capabilities at runtime.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use log::warn;
+
+/// A registered RPC handler. Plain `fn` pointers (rather than boxed
+/// closures) so a lookup can be copied out of the registry and called
+/// without holding the registry's lock -- handlers like `reset`/`dfu_*`
+/// can run for a while and shouldn't keep interrupts masked that long.
+pub type Handler = fn(Vec<u8>) -> Vec<u8>;
+
+static REGISTRY: Mutex<RefCell<BTreeMap<String, Handler>>> =
+    Mutex::new(RefCell::new(BTreeMap::new()));
+
+/// Registers `handler` under `name`, overwriting any existing registration.
+pub fn register(name: &str, handler: Handler) {
+    critical_section::with(|cs| {
+        REGISTRY.borrow_ref_mut(cs).insert(name.to_string(), handler);
+    });
+}
+
+/// Looks up and calls the handler registered for `name`, or logs a warning
+/// and returns an empty reply if nothing is registered under that name.
+pub fn dispatch(name: &str, data: Vec<u8>) -> Vec<u8> {
+    if name == "list_functions" {
+        return list_functions();
+    }
+    let handler = critical_section::with(|cs| REGISTRY.borrow_ref(cs).get(name).copied());
+    match handler {
+        Some(handler) => handler(data),
+        None => {
+            warn!("Function {name} does not exist");
+            Vec::new()
+        }
+    }
+}
+
+/// Built-in RPC returning the names of every registered handler (plus
+/// itself), newline-separated, so the host can discover the device's
+/// capabilities without a hardcoded list.
+fn list_functions() -> Vec<u8> {
+    let mut names =
+        critical_section::with(|cs| REGISTRY.borrow_ref(cs).keys().cloned().collect::<Vec<_>>());
+    names.push("list_functions".to_string());
+    names.sort_unstable();
+    names.join("\n").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    // `REGISTRY` is a single process-wide static, so tests that touch it
+    // need to be serialized against each other to avoid one test's
+    // registrations leaking into another's assertions.
+    static TEST_LOCK: StdMutex<()> = StdMutex::new(());
+
+    fn ok(_: Vec<u8>) -> Vec<u8> {
+        alloc::vec![1]
+    }
+
+    fn err(_: Vec<u8>) -> Vec<u8> {
+        alloc::vec![0]
+    }
+
+    #[test]
+    fn registering_the_same_name_twice_overwrites_the_handler() {
+        let _guard = TEST_LOCK.lock().unwrap();
+        register("dispatch_test_overwrite", ok);
+        register("dispatch_test_overwrite", err);
+        assert_eq!(dispatch("dispatch_test_overwrite", Vec::new()), alloc::vec![0]);
+    }
+
+    #[test]
+    fn dispatching_an_unregistered_name_returns_empty() {
+        let _guard = TEST_LOCK.lock().unwrap();
+        assert_eq!(
+            dispatch("dispatch_test_does_not_exist", Vec::new()),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn list_functions_is_sorted_and_includes_itself() {
+        let _guard = TEST_LOCK.lock().unwrap();
+        register("dispatch_test_zzz", ok);
+        register("dispatch_test_aaa", ok);
+
+        let listing = String::from_utf8(dispatch("list_functions", Vec::new())).unwrap();
+        let names: Vec<&str> = listing.lines().collect();
+
+        assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(names.contains(&"list_functions"));
+        assert!(names.contains(&"dispatch_test_zzz"));
+        assert!(names.contains(&"dispatch_test_aaa"));
+    }
+}
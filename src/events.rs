@@ -1,6 +1,28 @@
+use crate::dfu;
+use crate::dispatch;
 use crate::hardware::Hardware;
+use alloc::vec;
 use alloc::vec::Vec;
-use log::{debug, trace};
+use log::{debug, trace, warn};
+
+/// Registers every handler this module exposes into the RPC dispatch
+/// table. Call once at startup, before the serial task starts reading.
+///
+/// The `dfu_*` handlers are registered even though they can't complete a
+/// swap end-to-end yet: [`crate::hardware::Flash`] doesn't drive real
+/// erase/program, so `dfu_begin`/`dfu_write`/`dfu_commit` always report
+/// failure back to the host rather than staging anything. They're wired up
+/// now so the RPC surface and `dfu::BootState` bookkeeping are in place for
+/// when the flash driver lands, not because updates work today.
+pub fn register_all() {
+    dispatch::register("set_led", set_led);
+    dispatch::register("reset", |data| reset(data));
+    dispatch::register("dfu_begin", dfu_begin);
+    dispatch::register("dfu_write", dfu_write);
+    dispatch::register("dfu_commit", dfu_commit);
+    dispatch::register("dfu_state", dfu_state);
+    dispatch::register("mark_booted", mark_booted);
+}
 
 pub fn set_led(data: Vec<u8>) -> Vec<u8> {
     critical_section::with(|cs| {
@@ -26,6 +48,97 @@ pub fn set_led(data: Vec<u8>) -> Vec<u8> {
     Vec::new()
 }
 
+/// Erases the DFU staging partition and begins accepting `dfu_write`
+/// chunks for an image of `len` bytes. `data` is a little-endian `u32`
+/// image length.
+pub fn dfu_begin(data: Vec<u8>) -> Vec<u8> {
+    let Some(len) = data.get(0..4).map(|b| u32::from_le_bytes(b.try_into().unwrap())) else {
+        warn!("dfu_begin called with invalid argument");
+        return vec![0];
+    };
+    if len > dfu::layout::DFU_PARTITION_LEN {
+        warn!("dfu_begin: image of {len} bytes does not fit the DFU partition");
+        return vec![0];
+    }
+    critical_section::with(|cs| {
+        let hardware = Hardware::get();
+        let mut hardware = hardware.borrow_ref_mut(cs);
+        match dfu::begin(&mut hardware.flash, len) {
+            Ok(()) => vec![1],
+            Err(error) => {
+                warn!("dfu_begin: erase failed: {error:?}");
+                vec![0]
+            }
+        }
+    })
+}
+
+/// Writes one chunk of the staged image. `data` is a little-endian `u32`
+/// offset (relative to the start of the DFU partition) followed by the
+/// chunk bytes.
+pub fn dfu_write(data: Vec<u8>) -> Vec<u8> {
+    if data.len() < 4 {
+        warn!("dfu_write called with invalid argument");
+        return vec![0];
+    }
+    let offset = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    critical_section::with(|cs| {
+        let hardware = Hardware::get();
+        let mut hardware = hardware.borrow_ref_mut(cs);
+        match dfu::write_chunk(&mut hardware.flash, offset, &data[4..]) {
+            Ok(()) => vec![1],
+            Err(error) => {
+                warn!("dfu_write: write failed: {error:?}");
+                vec![0]
+            }
+        }
+    })
+}
+
+/// Records a pending-swap marker for the staged image and resets the board
+/// so the (future) bootloader stage can perform the swap. Only resets on a
+/// successfully written marker; if the marker write fails, the host gets an
+/// error reply instead of a board that reboots without actually staging
+/// anything.
+pub fn dfu_commit(_: Vec<u8>) -> Vec<u8> {
+    debug!("called dfu_commit");
+    let result = critical_section::with(|cs| {
+        let hardware = Hardware::get();
+        let mut hardware = hardware.borrow_ref_mut(cs);
+        dfu::commit(&mut hardware.flash)
+    });
+    match result {
+        Ok(()) => cortex_m::peripheral::SCB::sys_reset(),
+        Err(error) => {
+            warn!("dfu_commit: failed to record swap marker: {error:?}");
+            vec![0]
+        }
+    }
+}
+
+/// Reports the [`dfu::BootState`] this boot resolved to, so the host can
+/// tell whether it's talking to a freshly-swapped image awaiting
+/// self-test, a confirmed image, or a swap that was never confirmed.
+pub fn dfu_state(_: Vec<u8>) -> Vec<u8> {
+    vec![dfu::state() as u8]
+}
+
+/// Confirms the currently-running image, so the next boot reports `Boot`
+/// instead of `DfuDetached`. No rollback is performed either way.
+pub fn mark_booted(_: Vec<u8>) -> Vec<u8> {
+    critical_section::with(|cs| {
+        let hardware = Hardware::get();
+        let mut hardware = hardware.borrow_ref_mut(cs);
+        match dfu::mark_booted(&mut hardware.flash) {
+            Ok(()) => vec![1],
+            Err(error) => {
+                warn!("mark_booted: failed to persist confirmation: {error:?}");
+                vec![0]
+            }
+        }
+    })
+}
+
 pub fn reset(_: Vec<u8>) -> ! {
     debug!("called reset");
     critical_section::with(|cs| {
@@ -6,9 +6,85 @@ use critical_section::Mutex;
 use lazy_static::lazy_static;
 use teensy4_bsp::{pins, Led};
 
+/// Base address of the FlexSPI memory-mapped flash window, i.e. where flash
+/// offset `0` is visible to loads/stores.
+const FLEXSPI_FLASH_BASE: usize = 0x6000_0000;
+
+/// Size of the Teensy 4.0's onboard QSPI NOR flash. Bounds checks are
+/// against this, not `FLEXSPI_FLASH_BASE` -- the base is where flash is
+/// mapped into the address space, not how big it is.
+const FLASH_SIZE: usize = 0x0020_0000; // 2 MiB
+
+#[derive(Debug)]
+pub enum FlashError {
+    /// `offset`/`len` fell outside the flash (or, for DFU chunk writes,
+    /// outside the DFU partition or the staged image length), or overflowed
+    /// computing the end of the access.
+    OutOfRange,
+    /// Flash erase/program access isn't wired up for this board yet.
+    Unimplemented,
+}
+
+/// Read/write access to the external NOR flash the firmware boots from,
+/// used by the [`crate::dfu`] subsystem to stage and query firmware
+/// updates. Reads are plain loads through the FlexSPI's memory-mapped
+/// window; erase/write go through the flash's program/erase sequence,
+/// which must not touch the sectors the running image executes out of.
+///
+/// Only `read` is implemented today. `erase`/`write` report
+/// [`FlashError::Unimplemented`] -- driving the real FlexSPI NOR
+/// erase/program sequence (which has to run from RAM, since XIP can't
+/// fetch instructions from a sector mid-erase) is follow-up hardware
+/// bring-up, not yet done here. Until then, `dfu_write`/`dfu_commit`
+/// report failure rather than pretending to have staged anything.
+pub struct Flash;
+
+impl Flash {
+    fn new() -> Self {
+        Self
+    }
+
+    /// Reads `buf.len()` bytes starting at flash offset `offset`.
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let end = (offset as usize)
+            .checked_add(buf.len())
+            .ok_or(FlashError::OutOfRange)?;
+        if end > FLASH_SIZE {
+            return Err(FlashError::OutOfRange);
+        }
+        let src = (FLEXSPI_FLASH_BASE + offset as usize) as *const u8;
+        // Safety: `offset`/`buf.len()` were just checked to land inside the
+        // memory-mapped flash window, which is always readable.
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Erases the sector(s) covering `offset..offset + len`.
+    ///
+    /// # Safety
+    ///
+    /// `offset`/`len` must stay within a partition reserved for DFU use
+    /// (see `dfu::layout`); erasing the running application image would
+    /// brick the board.
+    pub unsafe fn erase(&mut self, _offset: u32, _len: u32) -> Result<(), FlashError> {
+        Err(FlashError::Unimplemented)
+    }
+
+    /// Programs `data` starting at flash offset `offset`. The target range
+    /// must already be erased.
+    ///
+    /// # Safety
+    ///
+    /// Same caveats as [`Self::erase`].
+    pub unsafe fn write(&mut self, _offset: u32, _data: &[u8]) -> Result<(), FlashError> {
+        Err(FlashError::Unimplemented)
+    }
+}
+
 pub struct Hardware {
     pub led: Led,
     pub systick: Delay,
+    pub flash: Flash,
 }
 
 impl Hardware {
@@ -34,6 +110,7 @@ impl Hardware {
                 teensy4_bsp::EXT_SYSTICK_HZ,
                 SystClkSource::External,
             ),
+            flash: Flash::new(),
         }
     }
 }
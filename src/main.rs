@@ -1,47 +1,112 @@
 //! BallBot teensy component
+//!
+//! Firmware-only bits (`no_std`/`no_main`, the hardware-backed modules, the
+//! executor, the allocator/panic plumbing) are compiled out under `cfg(test)`
+//! so `cargo test` can link a host test harness for the modules that don't
+//! touch hardware (`ring_buffer`, `dispatch`) instead of failing to link
+//! against `thumbv7em`-only crates like `cortex_m`/`teensy4_bsp`.
 
-#![no_std]
-#![no_main]
-#![feature(alloc_error_handler)]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
 
 extern crate alloc;
 
+#[cfg(not(test))]
+use crate::events::register_all;
+#[cfg(not(test))]
+use crate::hardware::Hardware;
+#[cfg(not(test))]
 use crate::logger::SerialLogger;
+#[cfg(not(test))]
 use crate::serial::SerialComm;
+#[cfg(not(test))]
 use alloc::format;
+#[cfg(not(test))]
 use core::alloc::Layout;
+#[cfg(not(test))]
 use core::panic::PanicInfo;
+#[cfg(not(test))]
 use cortex_m_rt as rt;
+#[cfg(not(test))]
+use embassy_executor::Executor;
+#[cfg(not(test))]
 use embedded_alloc::Heap;
+#[cfg(not(test))]
+use static_cell::StaticCell;
 
+#[cfg(not(test))]
+mod dfu;
+mod dispatch;
+#[cfg(not(test))]
 mod events;
+#[cfg(not(test))]
 mod hardware;
+#[cfg(not(test))]
 mod logger;
+mod ring_buffer;
+#[cfg(not(test))]
 mod serial;
 
+#[cfg(not(test))]
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+#[cfg(not(test))]
 #[rt::entry]
 fn main() -> ! {
     // Initialize the allocator BEFORE you use it.
     init_heap();
 
+    // The executor parks the core with `WFE` whenever every task is
+    // pending, and wakes on the next exception (namely `USB_OTG1`), so we
+    // idle instead of busy-polling for serial data.
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(serial_task()).unwrap();
+        spawner.spawn(serial::watch_line_coding()).unwrap();
+    })
+}
+
+/// Owns the serial link for the lifetime of the program: initializes
+/// logging, then dispatches incoming RPC frames forever.
+///
+/// Other work (future control loops, periodic housekeeping, ...) should be
+/// expressed as its own spawned task rather than folded in here.
+#[cfg(not(test))]
+#[embassy_executor::task]
+async fn serial_task() {
+    // Resolve whether the previous boot left a DFU swap pending before
+    // the host can ask about it via `dfu_state`.
+    critical_section::with(|cs| {
+        let hardware = Hardware::get();
+        let mut hardware = hardware.borrow_ref_mut(cs);
+        dfu::on_boot(&mut hardware.flash);
+    });
+
+    // Register every RPC handler before the host can reach any of them.
+    register_all();
+
     // See the `logging` module docs for more info.
     let serial = SerialComm::get().unwrap();
     SerialLogger::init(serial.clone());
 
     loop {
-        serial.read();
+        serial.read().await;
     }
 }
 
+#[cfg(not(test))]
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn oom(_: Layout) -> ! {
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+#[cfg(not(test))]
 fn init_heap() {
     use core::mem::MaybeUninit;
     const HEAP_SIZE: usize = 1024;
@@ -49,6 +114,7 @@ fn init_heap() {
     unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     SerialComm::get().unwrap().call("panic", format!("{info}"));
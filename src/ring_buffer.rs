@@ -0,0 +1,238 @@
+//! A lock-free single-producer/single-consumer byte ring buffer.
+//!
+//! Exactly one producer (`push_slice`/`push_frame`) and one consumer
+//! (`peek_slice` / `commit_pop`) may operate on a given buffer. The producer
+//! only ever advances `head`, the consumer only ever advances `tail`, and
+//! each side only reads the other's index (with `Acquire`/`Release`
+//! ordering), so no `critical_section` is needed on the fast path. The
+//! exceptions are [`OverflowPolicy::DropOldest`], which has the producer
+//! advance `tail` to make room, and [`Self::push_frame`], which enqueues
+//! several slices as one unit -- both run inside a `critical_section` so
+//! they can't race the consumer's own advances of the same index, or (for
+//! `push_frame`) race the consumer draining a partially-enqueued frame. One
+//! slot is always left empty to distinguish "full" from "empty" using just
+//! the two indices.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What to do when the producer catches up to the consumer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    /// Discard the incoming byte, keeping whatever is already queued.
+    DropNewest,
+    /// Advance `tail` to make room, discarding the oldest queued byte.
+    DropOldest,
+}
+
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    policy: OverflowPolicy,
+}
+
+// Safety: `head` is only ever written by the producer and `tail` by the
+// consumer, except where the producer advances `tail` to make room
+// (`OverflowPolicy::DropOldest`, `push_frame`), which it does inside a
+// `critical_section`; both sides only read the other's index with `Acquire`,
+// which synchronizes-with the `Release` store that published the
+// corresponding `buf` writes.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        Self {
+            buf: UnsafeCell::new([0u8; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            policy,
+        }
+    }
+
+    /// Producer-side: enqueues `data`, applying the configured
+    /// [`OverflowPolicy`] one byte at a time if the buffer fills up.
+    pub fn push_slice(&self, data: &[u8]) {
+        for &byte in data {
+            self.push_byte(byte);
+        }
+    }
+
+    fn push_byte(&self, byte: u8) {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        let tail = self.tail.load(Ordering::Acquire);
+        if next == tail {
+            match self.policy {
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::DropOldest => {
+                    // `tail` is otherwise only ever written by the consumer
+                    // (`commit_pop`/`clear`), so advancing it here would race
+                    // the ISR draining concurrently: we could stomp a tail
+                    // the consumer already moved past, resurrecting bytes it
+                    // already sent. A critical section keeps the consumer
+                    // from running between our re-check and the store below,
+                    // so this is the one spot the producer is allowed to
+                    // touch `tail`.
+                    critical_section::with(|_| {
+                        let tail = self.tail.load(Ordering::Acquire);
+                        if next == tail {
+                            self.tail.store((tail + 1) % N, Ordering::Release);
+                        }
+                    });
+                }
+            }
+        }
+        unsafe { (*self.buf.get())[head] = byte };
+        self.head.store(next, Ordering::Release);
+    }
+
+    /// Producer-side: enqueues `parts` concatenated as a single unit,
+    /// publishing them to the consumer with one `head` store.
+    ///
+    /// Multiple `push_slice` calls back to back would each publish as soon
+    /// as they run, so the `USB_OTG1` ISR's drain could observe (and
+    /// transmit) a partial frame if it preempted between them. Running the
+    /// whole thing -- overflow handling included -- inside a single
+    /// `critical_section` rules that out: the ISR's own drain also takes a
+    /// `critical_section`, so it either runs entirely before this or
+    /// entirely after, never in the middle.
+    ///
+    /// If `parts` is longer than the buffer can ever hold, the leading
+    /// bytes are dropped so the most recent `N - 1` bytes are kept, matching
+    /// [`Self::push_byte`]'s steady-state behavior under
+    /// [`OverflowPolicy::DropOldest`].
+    pub fn push_frame(&self, parts: &[&[u8]]) {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        if len == 0 {
+            return;
+        }
+        critical_section::with(|_| {
+            let head = self.head.load(Ordering::Relaxed);
+            let tail = self.tail.load(Ordering::Acquire);
+            let capacity = N - 1;
+            let free = (tail + N - head - 1) % N;
+
+            let mut skip = 0;
+            let mut tail = tail;
+            if len > free {
+                match self.policy {
+                    OverflowPolicy::DropNewest => return,
+                    OverflowPolicy::DropOldest => {
+                        let kept = len.min(capacity);
+                        tail = (tail + (kept - free)) % N;
+                        self.tail.store(tail, Ordering::Release);
+                        skip = len - kept;
+                    }
+                }
+            }
+
+            let buf = unsafe { &mut *self.buf.get() };
+            let mut head = head;
+            let mut seen = 0;
+            for part in parts {
+                for &byte in *part {
+                    if seen < skip {
+                        seen += 1;
+                        continue;
+                    }
+                    seen += 1;
+                    buf[head] = byte;
+                    head = (head + 1) % N;
+                }
+            }
+            self.head.store(head, Ordering::Release);
+        });
+    }
+
+    /// Consumer-side: copies up to `out.len()` queued bytes into `out`
+    /// *without* removing them, returning how many were copied. Pair with
+    /// [`Self::commit_pop`] once the caller knows how many bytes it
+    /// actually managed to hand off downstream.
+    pub fn peek_slice(&self, out: &mut [u8]) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let buf = unsafe { &*self.buf.get() };
+        let mut n = 0;
+        while n < out.len() && (tail + n) % N != head {
+            out[n] = buf[(tail + n) % N];
+            n += 1;
+        }
+        n
+    }
+
+    /// Consumer-side: removes the `n` bytes previously returned by
+    /// [`Self::peek_slice`].
+    pub fn commit_pop(&self, n: usize) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.tail.store((tail + n) % N, Ordering::Release);
+    }
+
+    /// Drops all queued bytes, for overflow recovery.
+    pub fn clear(&self) {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.store(head, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<const N: usize>(rb: &RingBuffer<N>) -> alloc::vec::Vec<u8> {
+        let mut out = [0u8; N];
+        let n = rb.peek_slice(&mut out);
+        rb.commit_pop(n);
+        out[..n].to_vec()
+    }
+
+    #[test]
+    fn drop_newest_discards_incoming_bytes_once_full() {
+        // Capacity is N - 1 usable slots.
+        let rb: RingBuffer<4> = RingBuffer::new(OverflowPolicy::DropNewest);
+        rb.push_slice(&[1, 2, 3, 4, 5]);
+        assert_eq!(drain(&rb), [1, 2, 3]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_most_recent_bytes() {
+        let rb: RingBuffer<4> = RingBuffer::new(OverflowPolicy::DropOldest);
+        rb.push_slice(&[1, 2, 3, 4, 5]);
+        assert_eq!(drain(&rb), [3, 4, 5]);
+    }
+
+    #[test]
+    fn push_frame_concatenates_parts_as_one_push() {
+        let rb: RingBuffer<8> = RingBuffer::new(OverflowPolicy::DropNewest);
+        rb.push_frame(&[&[1, 2], &[], &[3, 4, 5]]);
+        assert_eq!(drain(&rb), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn push_frame_drop_newest_discards_whole_frame_when_it_does_not_fit() {
+        let rb: RingBuffer<4> = RingBuffer::new(OverflowPolicy::DropNewest);
+        rb.push_slice(&[1, 2]);
+        rb.push_frame(&[&[3, 4], &[5]]);
+        assert_eq!(drain(&rb), [1, 2]);
+    }
+
+    #[test]
+    fn push_frame_drop_oldest_makes_room_for_the_whole_frame() {
+        let rb: RingBuffer<4> = RingBuffer::new(OverflowPolicy::DropOldest);
+        rb.push_slice(&[1, 2]);
+        rb.push_frame(&[&[3], &[4]]);
+        assert_eq!(drain(&rb), [2, 3, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_does_not_resurrect_bytes_the_consumer_already_drained() {
+        let rb: RingBuffer<4> = RingBuffer::new(OverflowPolicy::DropOldest);
+        rb.push_slice(&[1, 2, 3]);
+        // Consumer drains everything, as the USB_OTG1 ISR would.
+        assert_eq!(drain(&rb), [1, 2, 3]);
+        // A producer push that now sees an empty buffer must not touch
+        // `tail`, so only the newly pushed bytes come back out.
+        rb.push_slice(&[4, 5]);
+        assert_eq!(drain(&rb), [4, 5]);
+    }
+}
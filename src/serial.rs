@@ -4,48 +4,52 @@ use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::{cell::RefCell, fmt::Write};
+use core::cell::{Cell, RefCell};
 use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
 use lazy_static::lazy_static;
 use log::{error, info, warn};
 use teensy4_bsp::{hal::ral::usb::USB1, interrupt, usb};
 
-use crate::events;
+use crate::dispatch;
+use crate::ring_buffer::{OverflowPolicy, RingBuffer};
 
 pub const END: u8 = 0x00;
-pub const READY: u8 = 0x01;
 pub const FUNCTION_HEADER: u8 = 0x02;
 pub const RETURN_HEADER: u8 = 0x03;
 
-trait BlockingReader {
-    fn read_n(&mut self, num_bytes: usize) -> Result<Vec<u8>, usb::Error>;
-    fn read_n_blocking(&mut self, num_bytes: usize) -> Result<Vec<u8>, usb::Error>;
-}
+/// Raised by the `USB_OTG1` ISR after every `poller.poll()`, so tasks
+/// blocked on incoming data know to retry instead of spinning.
+static RX_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-impl BlockingReader for usb::Reader {
-    fn read_n(&mut self, num_bytes: usize) -> Result<Vec<u8>, usb::Error> {
-        if num_bytes == 0 {
-            return Ok(Vec::new());
-        }
-        let mut data = vec![0u8; num_bytes];
-        self.read(&mut data)?;
-        Ok(data)
-    }
-    fn read_n_blocking(&mut self, num_bytes: usize) -> Result<Vec<u8>, usb::Error> {
-        if num_bytes == 0 {
-            return Ok(Vec::new());
-        }
-        let mut data = vec![0u8; num_bytes];
-        while self.read(&mut data)? == 0 {}
-        Ok(data)
-    }
-}
+/// Framed bytes queued by `SerialComm::call`/`return_event`, drained by the
+/// `USB_OTG1` ISR. Only the ISR ever consumes from this buffer and only
+/// foreground code ever produces into it. Frames are enqueued with
+/// `push_frame`, which takes a short `critical_section` so the ISR never
+/// drains a partially-enqueued frame.
+static TX_RING: RingBuffer<256> = RingBuffer::new(OverflowPolicy::DropOldest);
+
+/// Whether the host currently has the port open, tracked from the CDC-ACM
+/// DTR control line rather than a hand-rolled handshake byte. `call`
+/// consults this so it stops queuing output the instant the host
+/// disconnects.
+static READY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Baud rate from the last line coding the host set, so a change can be
+/// detected and surfaced without re-announcing on every poll.
+static LAST_BAUD_RATE: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Raised by `sync_line_state` (run from the `USB_OTG1` ISR) whenever the
+/// host connects or changes its line coding. `watch_line_coding` is the
+/// only thing that ever waits on this, and is the only thing allowed to
+/// turn it into a `line_coding` announcement -- see the doc comment there
+/// for why that can't happen directly in the ISR.
+static LINE_STATE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 #[derive(Clone)]
 pub struct SerialComm {
     rx: Arc<Mutex<RefCell<usb::Reader>>>,
-    tx: Arc<Mutex<RefCell<usb::Writer>>>,
-    ready: Arc<Mutex<RefCell<bool>>>,
 }
 
 impl SerialComm {
@@ -62,132 +66,213 @@ impl SerialComm {
         lazy_static! {
             static ref SERIAL: SerialComm = usb::split(USB1::take().unwrap())
                 .map(|(poller, rx, tx)| {
-                    setup(poller);
+                    setup(poller, tx);
                     SerialComm {
                         rx: Arc::new(Mutex::new(RefCell::new(rx))),
-                        tx: Arc::new(Mutex::new(RefCell::new(tx))),
-                        ready: Arc::new(Mutex::new(RefCell::new(false))),
                     }
                 })
                 .unwrap();
         }
         Ok((*SERIAL).clone())
     }
-    pub fn ready(&self) {
-        critical_section::with(|cs| {
-            let tx = self.tx.clone();
-            let mut tx = tx.borrow(cs).borrow_mut();
-            tx.write([READY])
-                .expect("Failed to communicated with serial port");
-            // tx.flush().unwrap();
-            // we're ready to send communication now
-            *self.ready.borrow_ref_mut(cs) = true;
-        });
+    /// Reads exactly `num_bytes` from the endpoint, awaiting `RX_SIGNAL`
+    /// between polls instead of busy-looping while the host catches up.
+    async fn read_n(&self, num_bytes: usize) -> Result<Vec<u8>, usb::Error> {
+        if num_bytes == 0 {
+            return Ok(Vec::new());
+        }
+        let mut data = vec![0u8; num_bytes];
+        let mut filled = 0;
+        while filled < num_bytes {
+            let n = critical_section::with(|cs| {
+                let mut rx = self.rx.borrow_ref_mut(cs);
+                rx.read(&mut data[filled..])
+            })?;
+            if n == 0 {
+                RX_SIGNAL.wait().await;
+                continue;
+            }
+            filled += n;
+        }
+        Ok(data)
     }
-    pub fn read(&self) {
-        critical_section::with(|cs| {
-            let rx = self.rx.clone();
-            let mut rx = rx.borrow_ref_mut(cs);
-            match rx.read_n(1).map(|e| e[0]) {
-                Ok(READY) => {
-                    // if we receive ready event, we should respond back with ready
-                    self.ready();
-                }
-                Ok(FUNCTION_HEADER) => {
-                    // we've received a request to call a function.
-                    // we need to dispatch it and return a RETURN event.
-                    let function_len = rx.read_n_blocking(1).unwrap()[0];
-                    info!("got function length of {function_len}");
-
-                    let function =
-                        String::from_utf8(rx.read_n_blocking(function_len as usize).unwrap())
-                            .unwrap();
-                    info!("got function {function}");
-
-                    let data_len = rx.read_n_blocking(2).unwrap();
-                    let data_len = u16::from_le_bytes([data_len[0], data_len[1]]);
-                    info!("got data length of {data_len}");
-
-                    let data = rx.read_n_blocking(data_len as usize).unwrap();
-
-                    // read end
-                    rx.read_n_blocking(1).unwrap();
-
-                    let result = match function.as_str() {
-                        "set_led" => events::set_led(data),
-                        "reset" => events::reset(data),
-                        _ => {
-                            warn!("Function {function} does not exist");
-                            vec![]
-                        }
-                    };
-                    self.return_event(result);
-                }
-                Ok(END) => {}
-                Ok(b) => {
-                    // if we haven't matched, then the even had an invalid format (no event type)
-                    warn!("Received invalid event {b}");
-                    // flush buffer
-                    //TODO proper flush
+    /// Waits for and dispatches a single incoming framed event.
+    ///
+    /// Meant to be awaited in a loop from a spawned task: whenever no data
+    /// is available this parks on `RX_SIGNAL`, letting the executor idle
+    /// the core until the next `USB_OTG1` interrupt.
+    pub async fn read(&self) {
+        match self.read_n(1).await.map(|e| e[0]) {
+            Ok(FUNCTION_HEADER) => {
+                // we've received a request to call a function.
+                // we need to dispatch it and return a RETURN event.
+                let function_len = self.read_n(1).await.unwrap()[0];
+                info!("got function length of {function_len}");
+
+                let function =
+                    String::from_utf8(self.read_n(function_len as usize).await.unwrap()).unwrap();
+                info!("got function {function}");
+
+                let data_len = self.read_n(2).await.unwrap();
+                let data_len = u16::from_le_bytes([data_len[0], data_len[1]]);
+                info!("got data length of {data_len}");
+
+                let data = self.read_n(data_len as usize).await.unwrap();
+
+                // read end
+                self.read_n(1).await.unwrap();
+
+                let result = dispatch::dispatch(&function, data);
+                self.return_event(result);
+            }
+            Ok(END) => {}
+            Ok(b) => {
+                // if we haven't matched, then the even had an invalid format (no event type)
+                warn!("Received invalid event {b}");
+                // flush buffer
+                //TODO proper flush
+                critical_section::with(|cs| {
+                    let mut rx = self.rx.borrow_ref_mut(cs);
                     let buffer = [0u8; 1];
                     while rx.read(buffer).unwrap() > 0 {}
-                }
-                Err(error) => {
-                    error!("Error reading from serial: {:?}", error);
-                }
+                });
             }
-        });
+            Err(error) => {
+                error!("Error reading from serial: {:?}", error);
+            }
+        }
     }
     fn return_event<B: AsRef<[u8]>>(&self, data: B) {
-        critical_section::with(|cs| {
-            let tx = self.tx.clone();
-            let mut tx = tx.borrow(cs).borrow_mut();
-            tx.write([RETURN_HEADER]).unwrap();
-            tx.write(data).unwrap();
-        });
+        TX_RING.push_frame(&[&[RETURN_HEADER], data.as_ref()]);
     }
     pub fn call<B: AsRef<[u8]>>(&self, function: &str, data: B) {
-        critical_section::with(|cs| {
-            // make sure serial is ready to receive
-            if *self.ready.borrow_ref(cs) {
-                let tx = self.tx.clone();
-                let mut tx = tx.borrow(cs).borrow_mut();
-                tx.write([
-                    FUNCTION_HEADER,
-                    function
-                        .len()
-                        .try_into()
-                        .expect("Function name must be less than 255 characters"),
-                ])
-                .unwrap();
-                write!(tx, "{function}").unwrap();
-                let len: u16 = data.as_ref().len() as u16;
-                tx.write(len.to_le_bytes()).unwrap();
-                tx.write(data).unwrap();
-                tx.write([END]).unwrap();
-                // tx.flush().unwrap();
-            }
+        // make sure the host currently has the port open
+        if critical_section::with(|cs| READY.borrow(cs).get()) {
+            push_call_frame(function, data.as_ref());
+        }
+    }
+}
+
+/// Queues a `FUNCTION_HEADER` frame, shared by `SerialComm::call` and the
+/// line-coding announcement in `watch_line_coding`.
+///
+/// Must only ever be called from foreground (non-interrupt) code: it
+/// writes into `TX_RING` via `push_frame`, which is only safe with a
+/// single producer. The `USB_OTG1` ISR is `TX_RING`'s sole *consumer* --
+/// see the doc comment on `TX_RING` above. `push_frame` enqueues the whole
+/// frame as one unit, so the ISR's drain can never observe a partial frame.
+fn push_call_frame(function: &str, data: &[u8]) {
+    let header = [
+        FUNCTION_HEADER,
+        function
+            .len()
+            .try_into()
+            .expect("Function name must be less than 255 characters"),
+    ];
+    let len: u16 = data.len() as u16;
+    let len_bytes = len.to_le_bytes();
+    TX_RING.push_frame(&[&header, function.as_bytes(), &len_bytes, data, &[END]]);
+}
+
+/// Drains as much of `TX_RING` as the USB endpoint will currently accept.
+///
+/// Bytes are only removed once `tx.write` reports they were actually
+/// accepted, so a full endpoint just leaves the remainder queued for the
+/// next poll instead of being dropped.
+fn drain_tx(tx: &mut usb::Writer) {
+    let mut chunk = [0u8; 64];
+    loop {
+        let available = TX_RING.peek_slice(&mut chunk);
+        if available == 0 {
+            break;
+        }
+        match tx.write(&chunk[..available]) {
+            Ok(0) => break,
+            Ok(written) => TX_RING.commit_pop(written),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Reconciles `READY` and `LAST_BAUD_RATE` against the host's current
+/// CDC-ACM control-line state, run right after every `poller.poll()`.
+///
+/// Setting `ready` off of DTR (rather than the old handshake byte) means
+/// the device stops queuing `log`/`call` output the instant the host
+/// closes the port, and starts again the instant it reopens it, with no
+/// protocol round trip. This only ever *signals* that a `line_coding`
+/// announcement is due -- it must not enqueue the announcement itself,
+/// since it runs in interrupt context (see `watch_line_coding`).
+fn sync_line_state(poller: &usb::Poller) {
+    let dtr = poller.dtr();
+    let just_connected =
+        critical_section::with(|cs| !READY.borrow(cs).replace(dtr) && dtr);
+
+    if !dtr {
+        // nothing is listening; drop whatever was still queued for it
+        TX_RING.clear();
+        return;
+    }
+
+    let baud_rate = poller.line_coding().data_rate();
+    let baud_changed = critical_section::with(|cs| {
+        LAST_BAUD_RATE.borrow(cs).replace(Some(baud_rate)) != Some(baud_rate)
+    });
+
+    if just_connected || baud_changed {
+        LINE_STATE_SIGNAL.signal(());
+    }
+}
+
+/// Re-announces the host's line coding over `call` whenever
+/// `sync_line_state` (run from the `USB_OTG1` ISR) observes a connect or
+/// baud-rate change.
+///
+/// This has to live in its own foreground task rather than in the ISR
+/// itself: announcing means enqueuing a frame with `push_call_frame`,
+/// which writes into `TX_RING` through the same single-producer path as
+/// `SerialComm::call`/`return_event`. The `USB_OTG1` ISR is `TX_RING`'s
+/// one consumer -- it must never also be a producer.
+#[embassy_executor::task]
+pub async fn watch_line_coding() {
+    loop {
+        LINE_STATE_SIGNAL.wait().await;
+        let baud_rate = critical_section::with(|cs| {
+            READY
+                .borrow(cs)
+                .get()
+                .then(|| LAST_BAUD_RATE.borrow(cs).get())
+                .flatten()
         });
+        if let Some(baud_rate) = baud_rate {
+            push_call_frame("line_coding", &baud_rate.to_le_bytes());
+        }
     }
 }
 
-/// Setup the USB ISR with the USB poller
-fn setup(poller: usb::Poller) {
+/// Setup the USB ISR with the USB poller and writer
+fn setup(poller: usb::Poller, tx: usb::Writer) {
     static POLLER: Mutex<RefCell<Option<usb::Poller>>> = Mutex::new(RefCell::new(None));
+    static TX: Mutex<RefCell<Option<usb::Writer>>> = Mutex::new(RefCell::new(None));
 
     #[cortex_m_rt::interrupt]
     fn USB_OTG1() {
         critical_section::with(|cs| {
-            POLLER
-                .borrow(cs)
-                .borrow_mut()
-                .as_mut()
-                .map(|poller| poller.poll());
+            if let Some(poller) = POLLER.borrow(cs).borrow_mut().as_mut() {
+                poller.poll();
+                sync_line_state(poller);
+            }
+            if let Some(tx) = TX.borrow(cs).borrow_mut().as_mut() {
+                drain_tx(tx);
+            }
         });
+        // wake any task waiting on `SerialComm::read_n`
+        RX_SIGNAL.signal(());
     }
 
     critical_section::with(|cs| {
         *POLLER.borrow(cs).borrow_mut() = Some(poller);
+        *TX.borrow(cs).borrow_mut() = Some(tx);
         // Safety: invoked in a critical section that also prepares the ISR
         // shared memory. ISR memory is ready by the time the ISR runs.
         unsafe { cortex_m::peripheral::NVIC::unmask(interrupt::USB_OTG1) };